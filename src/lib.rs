@@ -1,19 +1,103 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
-pub struct Color(pub u8, pub u8, pub u8);
+pub struct Color(pub u16, pub u16, pub u16);
 
 impl Color {
     pub const BLACK: Self = Self(0, 0, 0);
     pub const WHITE: Self = Self(255, 255, 255);
+
+    /// Rescale each channel from one maxval to another, e.g. to up-convert an
+    /// 8-bit color to 16-bit depth or quantize a 16-bit color back down.
+    pub fn scaled(self, from: u16, to: u16) -> Self {
+        let scale = |value: u16| -> u16 {
+            if from == to || from == 0 {
+                value
+            } else {
+                ((value as u32 * to as u32 + from as u32 / 2) / from as u32) as u16
+            }
+        };
+        Self(scale(self.0), scale(self.1), scale(self.2))
+    }
+}
+
+/// Errors that can arise while reading, writing, or indexing a [`Pixels`].
+#[derive(Debug)]
+pub enum Error {
+    /// An underlying I/O operation failed.
+    Io(std::io::Error),
+    /// The magic value did not identify a supported Portable PixMap format.
+    BadMagic(String),
+    /// A header line (dimensions or maxval) was malformed.
+    BadHeader(String),
+    /// The declared color channel max value is not supported.
+    UnsupportedMaxval(String),
+    /// The body ended before all of the declared pixels were read.
+    TruncatedBody,
+    /// The body contained more pixels than the header declared.
+    TrailingData,
+    /// A coordinate fell outside of the image.
+    OutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "io error: {error}"),
+            Error::BadMagic(magic) => write!(
+                f,
+                "magic value {magic:?} does not indicate that this file is a Portable PixMap file"
+            ),
+            Error::BadHeader(reason) => write!(f, "malformed header: {reason}"),
+            Error::UnsupportedMaxval(maxval) => {
+                write!(f, "unsupported color channel max value: {maxval}")
+            }
+            Error::TruncatedBody => write!(f, "less pixels in body than indicated in header"),
+            Error::TrailingData => write!(f, "more pixels in body than indicated in header"),
+            Error::OutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "x={x} y={y} is out of bounds of image with dimensions w={width} h={height}"
+            ),
+        }
+    }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// A convenience alias for results produced by this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
 pub struct Pixels {
     data: Vec<Color>,
     width: usize,
     height: usize,
+    maxval: u16,
 }
 
 impl Pixels {
@@ -22,6 +106,7 @@ impl Pixels {
             data: vec![Color::BLACK; width * height],
             width,
             height,
+            maxval: 255,
         }
     }
 
@@ -42,126 +127,638 @@ impl Pixels {
         self.data[idx] = color;
     }
 
+    /// Fallible counterpart to [`get`](Self::get) that returns [`Error::OutOfBounds`]
+    /// instead of panicking when the coordinate lies outside the image.
+    pub fn try_get(&self, x: usize, y: usize) -> Result<Color> {
+        let idx = self.try_idx(x, y)?;
+        Ok(self.data[idx])
+    }
+
+    /// Fallible counterpart to [`set`](Self::set) that returns [`Error::OutOfBounds`]
+    /// instead of panicking when the coordinate lies outside the image.
+    pub fn try_set(&mut self, x: usize, y: usize, color: Color) -> Result<()> {
+        let idx = self.try_idx(x, y)?;
+        self.data[idx] = color;
+        Ok(())
+    }
+
     fn idx(&self, x: usize, y: usize) -> usize {
+        match self.try_idx(x, y) {
+            Ok(idx) => idx,
+            Err(error) => panic!("{error}"),
+        }
+    }
+
+    fn try_idx(&self, x: usize, y: usize) -> Result<usize> {
         let idx = y * self.width + x;
         if idx >= self.data.len() {
-            panic!(
-                "x={x} y={y} is out of bounds of image with dimensions w={} h={}",
-                self.width, self.height
-            );
+            return Err(Error::OutOfBounds {
+                x,
+                y,
+                width: self.width,
+                height: self.height,
+            });
         }
-        idx
+        Ok(idx)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::new(file))
     }
 
-    pub fn read(path: impl AsRef<Path>) -> Self {
-        let file = File::open(path).expect("failed to open file");
-        let mut reader = BufReader::new(file);
+    /// Decode a PPM image from any buffered source — an in-memory
+    /// [`Cursor`](std::io::Cursor), a socket, or a decompression stream — rather
+    /// than a file on disk. This is the core of [`read`](Self::read).
+    pub fn from_reader(mut reader: impl BufRead) -> Result<Self> {
+        // The Netpbm family dispatches on its magic value: ASCII P1/P2/P3 and the
+        // binary P4/P5/P6 for bitmaps, grayscale, and RGB respectively. Header
+        // tokens are whitespace-delimited (tabs and newlines included) and may be
+        // interleaved with `#` comment lines, as emitted by ImageMagick and GIMP.
+        let (binary, kind) = match read_token(&mut reader)?.as_str() {
+            "P1" => (false, Kind::Bit),
+            "P2" => (false, Kind::Gray),
+            "P3" => (false, Kind::Rgb),
+            "P4" => (true, Kind::Bit),
+            "P5" => (true, Kind::Gray),
+            "P6" => (true, Kind::Rgb),
+            magic => return Err(Error::BadMagic(magic.to_owned())),
+        };
+
+        let width: usize = read_token(&mut reader)?
+            .parse()
+            .map_err(|_| Error::BadHeader("failed to parse width".to_owned()))?;
+        let height: usize = read_token(&mut reader)?
+            .parse()
+            .map_err(|_| Error::BadHeader("failed to parse height".to_owned()))?;
+
+        // Bitmaps have no maxval token; every sample is implicitly 0 or 1.
+        let maxval: u16 = if kind == Kind::Bit {
+            1
+        } else {
+            let maxval_token = read_token(&mut reader)?;
+            match maxval_token.parse() {
+                // The spec allows any maxval in 1..=65535; values above 255 use
+                // two bytes per channel in the binary form.
+                Ok(maxval @ 1..=65535) => maxval,
+                _ => return Err(Error::UnsupportedMaxval(maxval_token)),
+            }
+        };
+        let wide = maxval > 255;
+
+        let pixel_count = width * height;
+        let mut data = Vec::with_capacity(pixel_count);
+
+        if binary {
+            match kind {
+                // In P4 each row is packed into bytes, most-significant bit first
+                // and padded to a byte boundary; a set bit denotes black.
+                Kind::Bit => {
+                    let row_bytes = width.div_ceil(8);
+                    let mut body = vec![0u8; row_bytes * height];
+                    read_body(&mut reader, &mut body)?;
+
+                    for y in 0..height {
+                        for x in 0..width {
+                            let byte = body[y * row_bytes + x / 8];
+                            let bit = (byte >> (7 - (x % 8))) & 1;
+                            data.push(bit_color(bit));
+                        }
+                    }
+                }
+                // P5/P6 bodies are raw samples with no delimiters: one u8 per
+                // channel for maxval <= 255, or two big-endian bytes otherwise.
+                kind => {
+                    let channels = kind.channels();
+                    let sample_bytes = if wide { 2 } else { 1 };
+                    let mut body = vec![0u8; pixel_count * channels * sample_bytes];
+                    read_body(&mut reader, &mut body)?;
+
+                    for chunk in body.chunks_exact(channels * sample_bytes) {
+                        let sample = |i: usize| -> u16 {
+                            if wide {
+                                u16::from_be_bytes([chunk[i * 2], chunk[i * 2 + 1]])
+                            } else {
+                                chunk[i] as u16
+                            }
+                        };
+                        data.push(match kind {
+                            Kind::Gray => gray_color(sample(0)),
+                            _ => Color(sample(0), sample(1), sample(2)),
+                        });
+                    }
+                }
+            }
+        } else if kind == Kind::Rgb {
+            // P3 emits one "r g b" triple per line, matching this crate's writer.
+            for (idx, line) in reader.lines().enumerate() {
+                if idx >= pixel_count {
+                    return Err(Error::TrailingData);
+                }
 
-        let mut buf = String::new();
-        _ = reader
-            .read_line(&mut buf)
-            .expect("failed to read magic value line in header");
+                let line = line?;
+                let tokens: Vec<_> = line.split(' ').collect();
 
-        // read_line yields the trailing new line, so throughout this function we must explicitly strip it away
-        if &buf[..buf.len() - 1] != "P3" {
-            panic!("magic value does not indicate that this file is an ASCII Portable PixMap file");
+                if tokens.len() != 3 {
+                    return Err(Error::BadHeader(format!(
+                        "pixel line {idx} was in the wrong format"
+                    )));
+                }
+
+                let r: u16 = tokens[0]
+                    .parse()
+                    .map_err(|_| Error::BadHeader("failed to parse red channel".to_owned()))?;
+                let g: u16 = tokens[1]
+                    .parse()
+                    .map_err(|_| Error::BadHeader("failed to parse green channel".to_owned()))?;
+                let b: u16 = tokens[2]
+                    .parse()
+                    .map_err(|_| Error::BadHeader("failed to parse blue channel".to_owned()))?;
+
+                data.push(Color(r, g, b));
+            }
+
+            if data.len() < pixel_count {
+                return Err(Error::TruncatedBody);
+            }
+        } else {
+            // P1/P2 bodies are whitespace-delimited single-channel samples.
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest)?;
+
+            for token in rest.split_whitespace() {
+                if data.len() >= pixel_count {
+                    return Err(Error::TrailingData);
+                }
+
+                let sample: u16 = token.parse().map_err(|_| {
+                    Error::BadHeader("failed to parse grayscale sample".to_owned())
+                })?;
+                data.push(match kind {
+                    Kind::Bit => bit_color(sample as u8),
+                    _ => gray_color(sample),
+                });
+            }
+
+            if data.len() < pixel_count {
+                return Err(Error::TruncatedBody);
+            }
         }
 
-        buf.clear();
-        _ = reader
-            .read_line(&mut buf)
-            .expect("failed to read image size line in header");
+        Ok(Self {
+            data,
+            width,
+            height,
+            maxval,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.save_impl(path, false)
+    }
+
+    /// Save as a binary `P6` PixMap. The header is identical to the ASCII `P3`
+    /// form, but the body is raw bytes (three u8s per pixel with no delimiters),
+    /// which roughly quarters the file size.
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.save_impl(path, true)
+    }
+
+    /// Encode this image as an ASCII `P3` PixMap to any writer — a
+    /// [`Cursor`](std::io::Cursor), a socket, or a compression stream — rather
+    /// than a file on disk. This is the core of [`save`](Self::save).
+    pub fn write_to(&self, writer: impl Write) -> Result<()> {
+        self.write_to_impl(writer, false)
+    }
+
+    /// Encode this image as a binary `P6` PixMap to any writer, mirroring
+    /// [`save_binary`](Self::save_binary).
+    pub fn write_to_binary(&self, writer: impl Write) -> Result<()> {
+        self.write_to_impl(writer, true)
+    }
+
+    fn write_to_impl(&self, mut writer: impl Write, binary: bool) -> Result<()> {
+        // Magic value to indicate which Portable PixMap representation this uses.
+        let magic: &[u8] = if binary { b"P6\n" } else { b"P3\n" };
+        writer.write_all(magic)?;
 
-        let tokens: Vec<_> = buf[..buf.len() - 1].split(' ').collect();
-        if tokens.len() != 2 {
-            panic!("image dimensions in header were in the wrong format");
+        // Space-delimited width and height of the PixMap.
+        writer.write_all(format!("{} {}\n", self.width, self.height).as_bytes())?;
+
+        // The declared maximum value of each color channel; above 255 each sample
+        // occupies two bytes in the binary body.
+        writer.write_all(format!("{}\n", self.maxval).as_bytes())?;
+        let wide = self.maxval > 255;
+
+        if binary {
+            let mut body = Vec::with_capacity(self.data.len() * 3 * if wide { 2 } else { 1 });
+            for color in &self.data {
+                for channel in [color.0, color.1, color.2] {
+                    if wide {
+                        body.extend_from_slice(&channel.to_be_bytes());
+                    } else {
+                        body.push(channel as u8);
+                    }
+                }
+            }
+            writer.write_all(&body)?;
+        } else {
+            for color in &self.data {
+                writer.write_all(format!("{} {} {}\n", color.0, color.1, color.2).as_bytes())?;
+            }
         }
 
-        let width: usize = tokens[0].parse().expect("failed to parse width");
-        let height: usize = tokens[1].parse().expect("failed to parse height");
+        Ok(())
+    }
+
+    /// Save as a grayscale PGM, computing each pixel's Rec. 601 luminance. Useful
+    /// for depth buffers, masks, and luminance exports. Emits ASCII `P2`.
+    pub fn save_pgm(&self, path: impl AsRef<Path>) -> Result<()> {
+        Self::atomic_write(path, |file| self.write_pgm_impl(file, false))
+    }
 
-        buf.clear();
-        _ = reader
-            .read_line(&mut buf)
-            .expect("failed to read color channel max value in header");
+    /// Save as a binary `P5` grayscale PGM, one raw byte per pixel (two for
+    /// maxvals above 255).
+    pub fn save_pgm_binary(&self, path: impl AsRef<Path>) -> Result<()> {
+        Self::atomic_write(path, |file| self.write_pgm_impl(file, true))
+    }
 
-        if &buf[..buf.len() - 1] != "255" {
-            panic!("this library only supports color channel max values of 255 (u8)");
+    fn write_pgm_impl(&self, mut writer: impl Write, binary: bool) -> Result<()> {
+        let magic: &[u8] = if binary { b"P5\n" } else { b"P2\n" };
+        writer.write_all(magic)?;
+        writer.write_all(format!("{} {}\n", self.width, self.height).as_bytes())?;
+        writer.write_all(format!("{}\n", self.maxval).as_bytes())?;
+        let wide = self.maxval > 255;
+
+        if binary {
+            let mut body = Vec::with_capacity(self.data.len() * if wide { 2 } else { 1 });
+            for color in &self.data {
+                let gray = luminance(*color);
+                if wide {
+                    body.extend_from_slice(&gray.to_be_bytes());
+                } else {
+                    body.push(gray as u8);
+                }
+            }
+            writer.write_all(&body)?;
+        } else {
+            for color in &self.data {
+                writer.write_all(format!("{}\n", luminance(*color)).as_bytes())?;
+            }
         }
 
-        let pixel_count = width * height;
-        let mut data = Vec::with_capacity(pixel_count);
+        Ok(())
+    }
 
-        for (idx, line) in reader.lines().enumerate() {
-            if idx >= pixel_count {
-                panic!("more pixels in body than indicated in header");
+    fn save_impl(&self, path: impl AsRef<Path>, binary: bool) -> Result<()> {
+        Self::atomic_write(path, |file| self.write_to_impl(file, binary))
+    }
+
+    /// Write to a temporary sibling file and atomically swap it into `path`, so
+    /// an interrupted save never leaves a half-written image behind.
+    fn atomic_write(
+        path: impl AsRef<Path>,
+        write: impl FnOnce(File) -> Result<()>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+
+        // Append ".tmp" to the whole file name so paths with no extension (or a
+        // non-UTF-8 one) are handled without panicking.
+        let mut temp_name = path
+            .file_name()
+            .ok_or_else(|| Error::BadHeader("output path has no file name".to_owned()))?
+            .to_owned();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = File::create(&temp_path)?;
+        write(file)?;
+
+        std::fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Read a QOI (Quite OK Image) file into a [`Pixels`].
+    pub fn read_qoi(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Self::decode_qoi(&bytes)
+    }
+
+    /// Save as a QOI (Quite OK Image) file — a compact, fast, lossless format
+    /// that requires no external dependencies.
+    pub fn save_qoi(&self, path: impl AsRef<Path>) -> Result<()> {
+        let bytes = self.encode_qoi();
+        Self::atomic_write(path, |mut file| {
+            file.write_all(&bytes)?;
+            Ok(())
+        })
+    }
+
+    fn encode_qoi(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        // 14-byte header: magic, width, height, channels, colorspace.
+        out.extend_from_slice(b"qoif");
+        out.extend_from_slice(&(self.width as u32).to_be_bytes());
+        out.extend_from_slice(&(self.height as u32).to_be_bytes());
+        out.push(3); // channels: RGB
+        out.push(0); // colorspace: sRGB with linear alpha
+
+        // The running array and previous pixel both start at opaque black, which
+        // is the spec's zero-init for the RGB channels. Because `Color` carries no
+        // alpha (it is fixed at 255), the emitted stream is byte-compatible with a
+        // spec decoder reading 3-channel output.
+        let mut index = [Color::BLACK; 64];
+        let mut previous = Color::BLACK;
+        let mut run: u8 = 0;
+
+        for (i, &pixel) in self.data.iter().enumerate() {
+            if pixel == previous {
+                run += 1;
+                // A run is 6 bits with a bias of -1, and is capped at 62 so the
+                // encoded byte never collides with the QOI_OP_RGB/RGBA tags.
+                if run == 62 || i == self.data.len() - 1 {
+                    out.push(QOI_OP_RUN | (run - 1));
+                    run = 0;
+                }
+                continue;
             }
 
-            let line = line.expect("failed to read pixel line");
-            let tokens: Vec<_> = line.split(' ').collect();
+            if run > 0 {
+                out.push(QOI_OP_RUN | (run - 1));
+                run = 0;
+            }
 
-            if tokens.len() != 3 {
-                panic!("pixel line {idx} was in the wrong format");
+            let hash = qoi_hash(pixel);
+            if index[hash] == pixel {
+                // QOI_OP_INDEX: tag 0b00, so the byte is just the 6-bit hash.
+                out.push(hash as u8);
+            } else {
+                let dr = pixel.0 as i16 - previous.0 as i16;
+                let dg = pixel.1 as i16 - previous.1 as i16;
+                let db = pixel.2 as i16 - previous.2 as i16;
+                let dr_dg = dr - dg;
+                let db_dg = db - dg;
+
+                if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                    out.push(
+                        QOI_OP_DIFF
+                            | (((dr + 2) as u8) << 4)
+                            | (((dg + 2) as u8) << 2)
+                            | ((db + 2) as u8),
+                    );
+                } else if (-32..=31).contains(&dg)
+                    && (-8..=7).contains(&dr_dg)
+                    && (-8..=7).contains(&db_dg)
+                {
+                    out.push(QOI_OP_LUMA | ((dg + 32) as u8));
+                    out.push((((dr_dg + 8) as u8) << 4) | ((db_dg + 8) as u8));
+                } else {
+                    out.push(QOI_OP_RGB);
+                    out.push(pixel.0 as u8);
+                    out.push(pixel.1 as u8);
+                    out.push(pixel.2 as u8);
+                }
             }
 
-            let r: u8 = tokens[0].parse().expect("failed to parse red channel");
-            let g: u8 = tokens[1].parse().expect("failed to parse green channel");
-            let b: u8 = tokens[2].parse().expect("failed to parse blue channel");
+            index[hash] = pixel;
+            previous = pixel;
+        }
 
-            data.push(Color(r, g, b));
+        // 8-byte end marker: seven 0x00 bytes followed by a single 0x01.
+        out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        out
+    }
+
+    fn decode_qoi(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 14 || &bytes[..4] != b"qoif" {
+            let magic = String::from_utf8_lossy(&bytes[..bytes.len().min(4)]).into_owned();
+            return Err(Error::BadMagic(magic));
         }
 
-        if data.len() < pixel_count {
-            panic!("less pixels in body than indicated in header");
+        let width = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let height = u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        // Since `Color` is RGB-only we fix channels to 3 and ignore the colorspace byte.
+        let pixel_count = width * height;
+
+        let mut data = Vec::with_capacity(pixel_count);
+        // Mirror the encoder's opaque-black zero-init (see `encode_qoi`).
+        let mut index = [Color::BLACK; 64];
+        let mut previous = Color::BLACK;
+        let mut p = 14;
+
+        while data.len() < pixel_count {
+            let Some(&tag) = bytes.get(p) else {
+                return Err(Error::TruncatedBody);
+            };
+            p += 1;
+
+            if tag == QOI_OP_RGB {
+                let rgb = bytes.get(p..p + 3).ok_or(Error::TruncatedBody)?;
+                previous = Color(rgb[0] as u16, rgb[1] as u16, rgb[2] as u16);
+                p += 3;
+                data.push(previous);
+                index[qoi_hash(previous)] = previous;
+            } else if tag == QOI_OP_RGBA {
+                // QOI_OP_RGBA — never emitted by this encoder, but tolerated on read
+                // by discarding the alpha channel.
+                let rgba = bytes.get(p..p + 4).ok_or(Error::TruncatedBody)?;
+                previous = Color(rgba[0] as u16, rgba[1] as u16, rgba[2] as u16);
+                p += 4;
+                data.push(previous);
+                index[qoi_hash(previous)] = previous;
+            } else {
+                match tag >> 6 {
+                    0b00 => {
+                        // QOI_OP_INDEX
+                        previous = index[(tag & 0x3F) as usize];
+                        data.push(previous);
+                    }
+                    0b01 => {
+                        // QOI_OP_DIFF
+                        let dr = ((tag >> 4) & 0x03) as i16 - 2;
+                        let dg = ((tag >> 2) & 0x03) as i16 - 2;
+                        let db = (tag & 0x03) as i16 - 2;
+                        previous = Color(
+                            (previous.0 as i16 + dr) as u8 as u16,
+                            (previous.1 as i16 + dg) as u8 as u16,
+                            (previous.2 as i16 + db) as u8 as u16,
+                        );
+                        data.push(previous);
+                        index[qoi_hash(previous)] = previous;
+                    }
+                    0b10 => {
+                        // QOI_OP_LUMA
+                        let second = *bytes.get(p).ok_or(Error::TruncatedBody)?;
+                        p += 1;
+                        let dg = (tag & 0x3F) as i16 - 32;
+                        let dr = dg + ((second >> 4) & 0x0F) as i16 - 8;
+                        let db = dg + (second & 0x0F) as i16 - 8;
+                        previous = Color(
+                            (previous.0 as i16 + dr) as u8 as u16,
+                            (previous.1 as i16 + dg) as u8 as u16,
+                            (previous.2 as i16 + db) as u8 as u16,
+                        );
+                        data.push(previous);
+                        index[qoi_hash(previous)] = previous;
+                    }
+                    0b11 => {
+                        // QOI_OP_RUN
+                        let run = (tag & 0x3F) + 1;
+                        for _ in 0..run {
+                            if data.len() >= pixel_count {
+                                return Err(Error::TrailingData);
+                            }
+                            data.push(previous);
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
         }
 
-        Self {
+        Ok(Self {
             data,
             width,
             height,
+            // QOI is an 8-bit-per-channel format.
+            maxval: 255,
+        })
+    }
+
+    /// The declared maximum value of each color channel.
+    pub fn maxval(&self) -> u16 {
+        self.maxval
+    }
+
+    /// Rescale every pixel to a new channel depth, updating [`maxval`](Self::maxval).
+    /// Useful for up-converting 8-bit renders to 16-bit precision or back.
+    pub fn convert_maxval(&mut self, maxval: u16) {
+        if maxval == self.maxval || maxval == 0 {
+            return;
+        }
+        for color in &mut self.data {
+            *color = color.scaled(self.maxval, maxval);
         }
+        self.maxval = maxval;
     }
 
-    pub fn save(&self, path: impl AsRef<Path>) {
-        let mut temp_path = PathBuf::from(path.as_ref());
-        let extension = temp_path.extension().unwrap().to_str().unwrap().to_owned();
-        let extension = format!("{extension}.tmp");
-        temp_path.set_extension(extension);
+    pub fn width(&self) -> usize {
+        self.width
+    }
 
-        let mut file = File::create(&temp_path).expect("failed to create file");
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
 
-        // Magic value to indicate that this file is written using the ASCII Portable PixMap representation.
-        file.write_all(b"P3\n")
-            .expect("failed to write magic value to header");
+// QOI opcode tags. QOI_OP_INDEX has tag 0b00, so it is omitted here: its encoded
+// byte is simply the 6-bit hash with no bits to set.
+const QOI_OP_DIFF: u8 = 0x40; // 0b01......
+const QOI_OP_LUMA: u8 = 0x80; // 0b10......
+const QOI_OP_RUN: u8 = 0xC0; // 0b11......
+const QOI_OP_RGB: u8 = 0xFE;
+const QOI_OP_RGBA: u8 = 0xFF;
+
+/// The QOI running-array hash: indexes a 64-entry table of recently seen pixels.
+/// The `255 * 11` term is the spec's `a * 11` with alpha fixed at 255, since
+/// [`Color`] is RGB-only.
+fn qoi_hash(color: Color) -> usize {
+    (color.0 as usize * 3 + color.1 as usize * 5 + color.2 as usize * 7 + 255 * 11) & 63
+}
 
-        // Space-delimited width and height of the PixMap.
-        file.write_all(format!("{} {}\n", self.width, self.height).as_bytes())
-            .expect("failed to write image dimensions to header");
+/// The sample layout of a Netpbm magic value.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    /// Single-bit bitmap (P1/P4).
+    Bit,
+    /// Single-channel grayscale (P2/P5).
+    Gray,
+    /// Three-channel RGB (P3/P6).
+    Rgb,
+}
+
+impl Kind {
+    fn channels(self) -> usize {
+        match self {
+            Kind::Bit | Kind::Gray => 1,
+            Kind::Rgb => 3,
+        }
+    }
+}
 
-        // Each color channel is represented by a u8, which inherently has a maximum value of 255.
-        file.write_all(b"255\n")
-            .expect("failed to write color channel max value to header");
+/// Replicate a grayscale sample across all three RGB channels.
+fn gray_color(sample: u16) -> Color {
+    Color(sample, sample, sample)
+}
+
+/// Expand a PBM bit into a color: a set bit is black, a clear bit is white at
+/// the implicit maxval of 1.
+fn bit_color(bit: u8) -> Color {
+    if bit & 1 == 1 {
+        Color::BLACK
+    } else {
+        Color(1, 1, 1)
+    }
+}
 
-        for (idx, color) in self.data.iter().enumerate() {
-            if let Err(error) =
-                file.write_all(format!("{} {} {}\n", color.0, color.1, color.2).as_bytes())
-            {
-                let x = idx % self.width;
-                let y = idx / self.width;
-                panic!("failed to write pixel at x={x} y={y}: {error}");
+/// Read a single whitespace-delimited header token, skipping leading whitespace
+/// and `#` comment lines (comments run to the end of the line). Exactly one
+/// trailing whitespace byte is consumed as the delimiter, which for the binary
+/// formats leaves the reader positioned at the start of the pixel data. Returns
+/// [`Error::BadHeader`] at end of input rather than panicking, so a truncated or
+/// empty file surfaces as an error.
+fn read_token(reader: &mut impl BufRead) -> Result<String> {
+    let mut token = String::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            if token.is_empty() {
+                return Err(Error::BadHeader("unexpected end of header".to_owned()));
             }
+            break;
         }
 
-        std::fs::rename(temp_path, path).expect("failed to swap temp file");
+        let c = byte[0];
+        if token.is_empty() {
+            if c == b'#' {
+                // Skip the comment through the end of the line.
+                while byte[0] != b'\n' {
+                    if reader.read(&mut byte)? == 0 {
+                        return Err(Error::BadHeader("unexpected end of header".to_owned()));
+                    }
+                }
+                continue;
+            }
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            token.push(c as char);
+        } else if c.is_ascii_whitespace() {
+            break;
+        } else {
+            token.push(c as char);
+        }
     }
 
-    pub fn width(&self) -> usize {
-        self.width
-    }
+    Ok(token)
+}
 
-    pub fn height(&self) -> usize {
-        self.height
-    }
+/// Fill `body` from `reader`, mapping a short read to [`Error::TruncatedBody`].
+fn read_body(reader: &mut impl Read, body: &mut [u8]) -> Result<()> {
+    reader.read_exact(body).map_err(|error| {
+        if error.kind() == std::io::ErrorKind::UnexpectedEof {
+            Error::TruncatedBody
+        } else {
+            Error::Io(error)
+        }
+    })
+}
+
+/// Rec. 601 luminance of a color, used when exporting to single-channel formats.
+fn luminance(color: Color) -> u16 {
+    ((color.0 as u32 * 299 + color.1 as u32 * 587 + color.2 as u32 * 114) / 1000) as u16
 }
 
 pub struct Iter<'a> {
@@ -187,10 +784,11 @@ impl<'a> Iterator for Iter<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::Cursor;
 
     #[test]
     fn read() {
-        let pixels = Pixels::read("./test-fixtures/out.ppm");
+        let pixels = Pixels::read("./test-fixtures/out.ppm").unwrap();
         assert_eq!(pixels.width(), 91);
         assert_eq!(pixels.height(), 91);
     }
@@ -223,6 +821,116 @@ mod test {
             }
         }
 
-        pixels.save("test.ppm");
+        pixels.save("test.ppm").unwrap();
+    }
+
+    #[test]
+    fn qoi_round_trip() {
+        // A short sequence chosen to exercise every opcode: a leading run of
+        // black (RUN), a medium jump (LUMA), a small delta (DIFF), a large jump
+        // (RGB), a repeat of an earlier color still in the array (INDEX), then a
+        // trailing repeat (RUN).
+        let colors = [
+            Color(0, 0, 0),
+            Color(0, 0, 0),
+            Color(10, 10, 10),
+            Color(11, 10, 9),
+            Color(200, 5, 180),
+            Color(11, 10, 9),
+            Color(11, 10, 9),
+        ];
+
+        let mut pixels = Pixels::new(colors.len(), 1);
+        for (x, &color) in colors.iter().enumerate() {
+            pixels.set(x, 0, color);
+        }
+
+        let decoded = Pixels::decode_qoi(&pixels.encode_qoi()).unwrap();
+        assert_eq!(decoded.width(), colors.len());
+        assert_eq!(decoded.height(), 1);
+        for (x, &color) in colors.iter().enumerate() {
+            assert!(decoded.get(x, 0) == color);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_binary_round_trip() {
+        // Build a genuine 16-bit P6 source: maxval 65535 with two big-endian
+        // bytes per channel. `Pixels::new` hardcodes maxval 255, so decoding a
+        // real source is the only non-scaling way to obtain a 16-bit image.
+        let mut source = b"P6\n2 1\n65535\n".to_vec();
+        for channel in [10_000u16, 40_000, 65_535, 0, 1, 65_535] {
+            source.extend_from_slice(&channel.to_be_bytes());
+        }
+
+        let pixels = Pixels::from_reader(Cursor::new(source)).unwrap();
+        assert_eq!(pixels.maxval(), 65_535);
+        assert!(pixels.get(0, 0) == Color(10_000, 40_000, 65_535));
+        assert!(pixels.get(1, 0) == Color(0, 1, 65_535));
+
+        // Re-encoding and decoding must preserve the samples exactly.
+        let mut buf = Vec::new();
+        pixels.write_to_binary(&mut buf).unwrap();
+
+        let decoded = Pixels::from_reader(Cursor::new(buf)).unwrap();
+        assert_eq!(decoded.maxval(), 65_535);
+        assert!(decoded.get(0, 0) == Color(10_000, 40_000, 65_535));
+        assert!(decoded.get(1, 0) == Color(0, 1, 65_535));
+    }
+
+    #[test]
+    fn reader_writer_round_trip() {
+        let mut pixels = Pixels::new(3, 2);
+        pixels.set(0, 0, Color(1, 2, 3));
+        pixels.set(2, 1, Color(255, 128, 64));
+
+        let mut buf = Vec::new();
+        pixels.write_to(&mut buf).unwrap();
+
+        let decoded = Pixels::from_reader(Cursor::new(buf)).unwrap();
+        assert_eq!(decoded.width(), 3);
+        assert_eq!(decoded.height(), 2);
+        assert!(decoded.get(0, 0) == Color(1, 2, 3));
+        assert!(decoded.get(2, 1) == Color(255, 128, 64));
+    }
+
+    #[test]
+    fn pgm_ascii_decode_with_comment_and_tabs() {
+        // A `#` comment line and tab/newline separators, as other tools emit.
+        let source = b"P2\n# made by some tool\n2 2\t255\n0 128\t255\n64\n";
+        let pixels = Pixels::from_reader(Cursor::new(&source[..])).unwrap();
+
+        assert_eq!(pixels.width(), 2);
+        assert_eq!(pixels.height(), 2);
+        assert!(pixels.get(0, 0) == Color(0, 0, 0));
+        assert!(pixels.get(1, 0) == Color(128, 128, 128));
+        assert!(pixels.get(0, 1) == Color(255, 255, 255));
+        assert!(pixels.get(1, 1) == Color(64, 64, 64));
+    }
+
+    #[test]
+    fn pgm_binary_decode() {
+        let mut source = b"P5\n2 2\n255\n".to_vec();
+        source.extend_from_slice(&[0, 128, 255, 64]);
+        let pixels = Pixels::from_reader(Cursor::new(source)).unwrap();
+
+        assert!(pixels.get(0, 0) == Color(0, 0, 0));
+        assert!(pixels.get(1, 0) == Color(128, 128, 128));
+        assert!(pixels.get(0, 1) == Color(255, 255, 255));
+        assert!(pixels.get(1, 1) == Color(64, 64, 64));
+    }
+
+    #[test]
+    fn pbm_binary_decode() {
+        // Two rows, each packed into one byte, most-significant bit first. A set
+        // bit is black; a clear bit is white at the implicit maxval of 1.
+        let source = [b'P', b'4', b'\n', b'2', b' ', b'2', b'\n', 0b10_000000, 0b01_000000];
+        let pixels = Pixels::from_reader(Cursor::new(&source[..])).unwrap();
+
+        assert_eq!(pixels.maxval(), 1);
+        assert!(pixels.get(0, 0) == Color::BLACK);
+        assert!(pixels.get(1, 0) == Color(1, 1, 1));
+        assert!(pixels.get(0, 1) == Color(1, 1, 1));
+        assert!(pixels.get(1, 1) == Color::BLACK);
     }
 }